@@ -1,13 +1,89 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env, fs,
-    io::{stdin, stdout, BufRead, Write},
+    io::{stdin, stdout, BufRead, ErrorKind, Write},
 };
 
 use rand::prelude::*;
 
 use crate::{compile::Assembly, value::Value, vm::Env, RuntimeError, RuntimeResult};
 
+/// The kind of entry returned by [`IoBackend::read_dir`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+impl FileType {
+    /// The tag a Uiua script sees when it reads the type-tags array from [`read_dir_values`]
+    fn tag(self) -> f64 {
+        match self {
+            FileType::File => 0.0,
+            FileType::Directory => 1.0,
+            FileType::Symlink => 2.0,
+            FileType::Other => 3.0,
+        }
+    }
+}
+
+/// Convert an [`IoBackend::read_dir`] listing into the (names, type-tags) array pair a Uiua script receives
+pub fn read_dir_values(entries: Vec<(String, FileType)>) -> (Value, Value) {
+    let names: Vec<Value> = entries
+        .iter()
+        .map(|(name, _)| name.clone().into())
+        .collect();
+    let tags: Vec<Value> = entries.iter().map(|(_, ty)| ty.tag().into()).collect();
+    (names.into(), tags.into())
+}
+
+/// How [`IoBackend::write_file_with`] should open a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Create the file if it doesn't exist and overwrite its contents
+    Truncate,
+    /// Create the file if it doesn't exist and append to its contents
+    Append,
+    /// Create the file, failing with [`std::io::ErrorKind::AlreadyExists`] if it already exists
+    CreateNew,
+}
+
+/// Build the error reported by the default `IoBackend` file-IO methods
+fn unsupported(op: &str) -> RuntimeError {
+    RuntimeError::Io {
+        kind: ErrorKind::Unsupported,
+        msg: format!("{op} is not supported in this environment"),
+    }
+}
+
+/// The tag a Uiua script sees when it catches an `Io` error
+///
+/// Lets a catch handler branch on the kind of failure, e.g. treat `NotFound`
+/// as "create it" and `PermissionDenied` as fatal, without string-matching
+/// the message.
+fn io_kind_tag(kind: ErrorKind) -> f64 {
+    match kind {
+        ErrorKind::NotFound => 0.0,
+        ErrorKind::PermissionDenied => 1.0,
+        ErrorKind::AlreadyExists => 2.0,
+        ErrorKind::NotADirectory => 3.0,
+        ErrorKind::Unsupported => 4.0,
+        _ => 5.0,
+    }
+}
+
+impl RuntimeError {
+    /// The value a catch handler sees for this error, if it's an IO error
+    pub fn catch_tag(&self) -> Option<Value> {
+        match self {
+            RuntimeError::Io { kind, .. } => Some(io_kind_tag(*kind).into()),
+            _ => None,
+        }
+    }
+}
+
 #[allow(unused_variables)]
 pub trait IoBackend {
     fn print_str(&mut self, s: &str);
@@ -27,17 +103,52 @@ pub trait IoBackend {
     fn file_exists(&self, path: &str) -> bool {
         false
     }
+    /// List the entries of a directory along with their [`FileType`]
+    ///
+    /// `list_dir` is implemented in terms of this method, so backends only
+    /// need to override `read_dir`.
+    fn read_dir(&self, path: &str, env: &Env) -> RuntimeResult<Vec<(String, FileType)>> {
+        Err(unsupported("Directory listing"))
+    }
+    /// List the names of the entries in a directory
     fn list_dir(&self, path: &str, env: &Env) -> RuntimeResult<Vec<String>> {
-        Err(env.error("File IO not supported in this environment"))
+        Ok(self
+            .read_dir(path, env)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
     }
     fn is_file(&self, path: &str, env: &Env) -> RuntimeResult<bool> {
-        Err(env.error("File IO not supported in this environment"))
+        Err(unsupported("Checking file type"))
     }
     fn read_file(&mut self, path: &str, env: &Env) -> RuntimeResult<Vec<u8>> {
-        Err(env.error("File IO not supported in this environment"))
+        Err(unsupported("Reading a file"))
     }
+    /// Write a file, overwriting its contents
+    ///
+    /// Kept for source compatibility with callers that don't need a
+    /// [`WriteMode`]. Prefer [`IoBackend::write_file_with`], which backends
+    /// only need to override once.
     fn write_file(&mut self, path: &str, contents: Vec<u8>, env: &Env) -> RuntimeResult {
-        Err(env.error("File IO not supported in this environment"))
+        self.write_file_with(path, contents, WriteMode::Truncate, env)
+    }
+    /// Write a file under an explicit open [`WriteMode`]
+    fn write_file_with(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        mode: WriteMode,
+        env: &Env,
+    ) -> RuntimeResult {
+        Err(unsupported("Writing a file"))
+    }
+    /// Get the current working directory
+    fn cwd(&self, env: &Env) -> RuntimeResult<String> {
+        Err(unsupported("Getting the current directory"))
+    }
+    /// Change the current working directory
+    fn chdir(&mut self, path: &str, env: &Env) -> RuntimeResult {
+        Err(unsupported("Changing the current directory"))
     }
 }
 
@@ -66,6 +177,9 @@ where
     fn file_exists(&self, path: &str) -> bool {
         (**self).file_exists(path)
     }
+    fn read_dir(&self, path: &str, env: &Env) -> RuntimeResult<Vec<(String, FileType)>> {
+        (**self).read_dir(path, env)
+    }
     fn list_dir(&self, path: &str, env: &Env) -> RuntimeResult<Vec<String>> {
         (**self).list_dir(path, env)
     }
@@ -78,6 +192,21 @@ where
     fn write_file(&mut self, path: &str, contents: Vec<u8>, env: &Env) -> RuntimeResult {
         (**self).write_file(path, contents, env)
     }
+    fn write_file_with(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        mode: WriteMode,
+        env: &Env,
+    ) -> RuntimeResult {
+        (**self).write_file_with(path, contents, mode, env)
+    }
+    fn cwd(&self, env: &Env) -> RuntimeResult<String> {
+        (**self).cwd(env)
+    }
+    fn chdir(&mut self, path: &str, env: &Env) -> RuntimeResult {
+        (**self).chdir(path, env)
+    }
 }
 
 pub struct StdIo {
@@ -129,23 +258,342 @@ impl IoBackend for StdIo {
     fn file_exists(&self, path: &str) -> bool {
         fs::metadata(path).is_ok()
     }
-    fn is_file(&self, path: &str, env: &Env) -> RuntimeResult<bool> {
-        fs::metadata(path)
-            .map(|m| m.is_file())
-            .map_err(|e| env.error(e.to_string()))
+    fn is_file(&self, path: &str, _env: &Env) -> RuntimeResult<bool> {
+        fs::metadata(path).map(|m| m.is_file()).map_err(io_error)
+    }
+    fn read_dir(&self, path: &str, _env: &Env) -> RuntimeResult<Vec<(String, FileType)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path).map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            let file_type = entry
+                .file_type()
+                .map(|ft| {
+                    if ft.is_file() {
+                        FileType::File
+                    } else if ft.is_dir() {
+                        FileType::Directory
+                    } else if ft.is_symlink() {
+                        FileType::Symlink
+                    } else {
+                        FileType::Other
+                    }
+                })
+                .unwrap_or(FileType::Other);
+            entries.push((entry.path().to_string_lossy().into(), file_type));
+        }
+        Ok(entries)
     }
-    fn list_dir(&self, path: &str, env: &Env) -> RuntimeResult<Vec<String>> {
-        let mut paths = Vec::new();
-        for entry in fs::read_dir(path).map_err(|e| env.error(e.to_string()))? {
-            let entry = entry.map_err(|e| env.error(e.to_string()))?;
-            paths.push(entry.path().to_string_lossy().into());
+    fn read_file(&mut self, path: &str, _env: &Env) -> RuntimeResult<Vec<u8>> {
+        fs::read(path).map_err(io_error)
+    }
+    fn write_file(&mut self, path: &str, contents: Vec<u8>, env: &Env) -> RuntimeResult {
+        self.write_file_with(path, contents, WriteMode::Truncate, env)
+    }
+    fn write_file_with(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        mode: WriteMode,
+        _env: &Env,
+    ) -> RuntimeResult {
+        let mut options = fs::OpenOptions::new();
+        options.write(true);
+        match mode {
+            WriteMode::Truncate => options.create(true).truncate(true),
+            WriteMode::Append => options.create(true).append(true),
+            WriteMode::CreateNew => options.create_new(true),
+        };
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(&contents))
+            .map_err(io_error)
+    }
+    fn cwd(&self, _env: &Env) -> RuntimeResult<String> {
+        env::current_dir()
+            .map(|p| p.to_string_lossy().into_owned())
+            .map_err(io_error)
+    }
+    fn chdir(&mut self, path: &str, _env: &Env) -> RuntimeResult {
+        env::set_current_dir(path).map_err(io_error)
+    }
+}
+
+/// Preserve a `std::io::Error`'s [`ErrorKind`] instead of collapsing it to a string
+fn io_error(e: std::io::Error) -> RuntimeError {
+    RuntimeError::Io {
+        kind: e.kind(),
+        msg: e.to_string(),
+    }
+}
+
+/// An in-memory [`IoBackend`], rooted at `/`
+pub struct VirtualIo {
+    files: HashMap<String, Vec<u8>>,
+    dirs: HashSet<String>,
+    imports: HashMap<String, Vec<Value>>,
+    output: String,
+    rng: SmallRng,
+    cwd: String,
+}
+
+impl Default for VirtualIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualIo {
+    /// Create an empty virtual filesystem, rooted at `/`
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            dirs: HashSet::from([String::from("/")]),
+            imports: HashMap::new(),
+            output: String::new(),
+            rng: SmallRng::seed_from_u64(0),
+            cwd: "/".into(),
         }
-        Ok(paths)
     }
-    fn read_file(&mut self, path: &str, env: &Env) -> RuntimeResult<Vec<u8>> {
-        fs::read(path).map_err(|e| env.error(e.to_string()))
+    /// Preload a file into the virtual tree
+    pub fn preload(&mut self, path: impl Into<String>, contents: impl Into<Vec<u8>>) {
+        self.files.insert(path.into(), contents.into());
+    }
+    /// Preload a directory into the virtual tree
+    pub fn preload_dir(&mut self, path: impl Into<String>) {
+        self.dirs.insert(path.into());
+    }
+    /// Everything printed so far
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+    /// Resolve `path` against the logical cwd if it's relative
+    fn resolve(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            path.into()
+        } else {
+            format!("{}/{path}", self.cwd.trim_end_matches('/'))
+        }
+    }
+}
+
+impl IoBackend for VirtualIo {
+    fn print_str(&mut self, s: &str) {
+        self.output.push_str(s);
+    }
+    fn rand(&mut self) -> f64 {
+        self.rng.gen()
+    }
+    fn import(&mut self, path: &str, _env: &Env) -> RuntimeResult<Vec<Value>> {
+        let path = self.resolve(path);
+        if !self.imports.contains_key(&path) {
+            let source = self.files.get(&path).ok_or_else(|| not_found(&path))?;
+            let source = String::from_utf8_lossy(source).into_owned();
+            let (stack, _) = Assembly::load_str(&source)
+                .map_err(RuntimeError::Import)?
+                .run_with_backend(&mut *self)
+                .map_err(RuntimeError::Import)?;
+            self.imports.insert(path.clone(), stack);
+        }
+        Ok(self.imports[&path].clone())
+    }
+    fn file_exists(&self, path: &str) -> bool {
+        let path = self.resolve(path);
+        self.files.contains_key(&path) || self.dirs.contains(&path)
+    }
+    fn read_dir(&self, path: &str, env: &Env) -> RuntimeResult<Vec<(String, FileType)>> {
+        if self.is_file(path, env)? {
+            return Err(not_a_directory(&self.resolve(path)));
+        }
+        let path = self.resolve(path);
+        let prefix = if path.ends_with('/') {
+            path.clone()
+        } else {
+            format!("{path}/")
+        };
+        let mut entries: Vec<(String, FileType)> = self
+            .dirs
+            .iter()
+            .filter(|p| {
+                p.len() > prefix.len()
+                    && p.starts_with(&prefix)
+                    && p[prefix.len()..].find('/').is_none()
+            })
+            .map(|p| (p.clone(), FileType::Directory))
+            .collect();
+        entries.extend(
+            self.files
+                .keys()
+                .filter(|p| p.starts_with(&prefix) && p[prefix.len()..].find('/').is_none())
+                .map(|p| (p.clone(), FileType::File)),
+        );
+        Ok(entries)
+    }
+    fn is_file(&self, path: &str, _env: &Env) -> RuntimeResult<bool> {
+        let path = self.resolve(path);
+        if self.files.contains_key(&path) {
+            Ok(true)
+        } else if self.dirs.contains(&path) {
+            Ok(false)
+        } else {
+            Err(not_found(&path))
+        }
+    }
+    fn read_file(&mut self, path: &str, _env: &Env) -> RuntimeResult<Vec<u8>> {
+        let path = self.resolve(path);
+        self.files
+            .get(&path)
+            .cloned()
+            .ok_or_else(|| not_found(&path))
     }
     fn write_file(&mut self, path: &str, contents: Vec<u8>, env: &Env) -> RuntimeResult {
-        fs::write(path, contents).map_err(|e| env.error(e.to_string()))
+        self.write_file_with(path, contents, WriteMode::Truncate, env)
+    }
+    fn write_file_with(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        mode: WriteMode,
+        _env: &Env,
+    ) -> RuntimeResult {
+        let path = self.resolve(path);
+        match mode {
+            WriteMode::Truncate => {
+                self.files.insert(path, contents);
+            }
+            WriteMode::Append => {
+                self.files.entry(path).or_default().extend(contents);
+            }
+            WriteMode::CreateNew => {
+                if self.files.contains_key(&path) || self.dirs.contains(&path) {
+                    return Err(RuntimeError::Io {
+                        kind: ErrorKind::AlreadyExists,
+                        msg: format!("File already exists: {path}"),
+                    });
+                }
+                self.files.insert(path, contents);
+            }
+        }
+        Ok(())
+    }
+    fn cwd(&self, _env: &Env) -> RuntimeResult<String> {
+        Ok(self.cwd.clone())
+    }
+    fn chdir(&mut self, path: &str, env: &Env) -> RuntimeResult {
+        if self.is_file(path, env)? {
+            return Err(not_a_directory(&self.resolve(path)));
+        }
+        let path = self.resolve(path);
+        if !self.dirs.contains(&path) {
+            return Err(not_found(&path));
+        }
+        self.cwd = path;
+        Ok(())
+    }
+}
+
+/// Build the error reported when a [`VirtualIo`] path isn't in the tree
+fn not_found(path: &str) -> RuntimeError {
+    RuntimeError::Io {
+        kind: ErrorKind::NotFound,
+        msg: format!("File not found: {path}"),
+    }
+}
+
+/// Build the error reported when a [`VirtualIo`] directory operation is given a file path
+fn not_a_directory(path: &str) -> RuntimeError {
+    RuntimeError::Io {
+        kind: ErrorKind::NotADirectory,
+        msg: format!("Not a directory: {path}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_dir_on_a_file_errors() {
+        let mut io = VirtualIo::new();
+        io.preload("/foo.txt", b"hello".to_vec());
+        let env = Env::default();
+        let err = io.read_dir("/foo.txt", &env).unwrap_err();
+        assert!(matches!(
+            err,
+            RuntimeError::Io {
+                kind: ErrorKind::NotADirectory,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn read_dir_lists_files_and_dirs() {
+        let mut io = VirtualIo::new();
+        io.preload_dir("/a");
+        io.preload("/a/one.txt", b"1".to_vec());
+        io.preload_dir("/a/sub");
+        let env = Env::default();
+        let mut entries = io.read_dir("/a", &env).unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            vec![
+                ("/a/one.txt".into(), FileType::File),
+                ("/a/sub".into(), FileType::Directory),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_file_with_append_extends_contents() {
+        let mut io = VirtualIo::new();
+        let env = Env::default();
+        io.write_file_with("/log.txt", b"a".to_vec(), WriteMode::Append, &env)
+            .unwrap();
+        io.write_file_with("/log.txt", b"b".to_vec(), WriteMode::Append, &env)
+            .unwrap();
+        assert_eq!(io.read_file("/log.txt", &env).unwrap(), b"ab");
+    }
+
+    #[test]
+    fn write_file_with_create_new_fails_if_exists() {
+        let mut io = VirtualIo::new();
+        let env = Env::default();
+        io.preload("/exists.txt", b"x".to_vec());
+        let err = io
+            .write_file_with("/exists.txt", b"y".to_vec(), WriteMode::CreateNew, &env)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            RuntimeError::Io {
+                kind: ErrorKind::AlreadyExists,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn chdir_resolves_relative_paths() {
+        let mut io = VirtualIo::new();
+        let env = Env::default();
+        io.preload_dir("/a");
+        io.preload("/a/one.txt", b"1".to_vec());
+        io.chdir("/a", &env).unwrap();
+        assert_eq!(io.cwd(&env).unwrap(), "/a");
+        assert_eq!(io.read_file("one.txt", &env).unwrap(), b"1");
+    }
+
+    #[test]
+    fn chdir_into_a_file_errors() {
+        let mut io = VirtualIo::new();
+        let env = Env::default();
+        io.preload("/one.txt", b"1".to_vec());
+        assert!(matches!(
+            io.chdir("/one.txt", &env).unwrap_err(),
+            RuntimeError::Io {
+                kind: ErrorKind::NotADirectory,
+                ..
+            }
+        ));
     }
 }